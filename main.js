@@ -85,8 +85,9 @@ function onSidecarLine(line) {
   }
 }
 
-// ── Binary egress: length-prefixed f32le frames over TCP ─────────────────────
-// Frame layout (matches sidecar try_write_app_audio_binary_frame):
+// ── Binary egress: length-prefixed PCM frames over TCP ───────────────────────
+// Frame layout, length_prefixed_pcm_v2 (matches sidecar
+// try_write_app_audio_binary_frame; protocol_ver is 2):
 //   [4]  payload_len     u32 LE   (total bytes after this field)
 //   [2]  session_id_len  u16 LE
 //   [N]  session_id      UTF-8
@@ -98,8 +99,19 @@ function onSidecarLine(line) {
 //   [4]  frame_count     u32 LE
 //   [4]  protocol_ver    u32 LE
 //   [4]  dropped_frames  u32 LE
+//   [4]  tag             u32 LE   (from audio_capture.start, 0 if unset)
+//   [4]  flags           u32 LE   (bit 0: more parts of this frame follow;
+//                                  bit 2: keyframe, always set for PCM)
 //   [4]  pcm_byte_len    u32 LE
-//   [P]  pcm data        f32le
+//   [P]  pcm data        f32le, or s16le/s24le/s32le when the session's
+//                                 format (start response, encoding_changed)
+//                                 says so
+// A session_id_len of 0 marks a control frame ([2] type, [4] body_len, JSON
+// body), e.g. the session hello sent on connect. Those are skipped here.
+// Frames over the session's maxBinaryFrameBytes arrive as consecutive parts with
+// the same sequence; their PCM is concatenated until a part without bit 0.
+
+let binaryFramePart = null; // { sessionId, sequence, frameCount, buffers }
 
 function parseBinaryFrames() {
   while (sidecarBinaryBuf.length >= 4) {
@@ -112,24 +124,41 @@ function parseBinaryFrames() {
     try {
       let o = 0;
       const sidLen = payload.readUInt16LE(o); o += 2;
+      if (sidLen === 0) continue; // control frame
       const sessionId = payload.slice(o, o + sidLen).toString('utf8'); o += sidLen;
       const tidLen = payload.readUInt16LE(o); o += 2;
       const targetId = payload.slice(o, o + tidLen).toString('utf8'); o += tidLen;
       const sequence = Number(payload.readBigUInt64LE(o)); o += 8;
       const sampleRate = payload.readUInt32LE(o); o += 4;
       const channels = payload.readUInt16LE(o); o += 2;
-      const frameCount = payload.readUInt32LE(o); o += 4;
+      const partFrameCount = payload.readUInt32LE(o); o += 4;
       const protocolVersion = payload.readUInt32LE(o); o += 4;
       const droppedFrameCount = payload.readUInt32LE(o); o += 4;
+      const tag = payload.readUInt32LE(o); o += 4;
+      const flags = payload.readUInt32LE(o); o += 4;
       const pcmByteLen = payload.readUInt32LE(o); o += 4;
-      const pcmBuffer = payload.slice(o, o + pcmByteLen);
+      let pcmBuffer = payload.slice(o, o + pcmByteLen);
+      let frameCount = partFrameCount;
+
+      const part = binaryFramePart;
+      binaryFramePart = null;
+      if (part && part.sessionId === sessionId && part.sequence === sequence) {
+        part.buffers.push(pcmBuffer);
+        part.frameCount += partFrameCount;
+        pcmBuffer = Buffer.concat(part.buffers);
+        frameCount = part.frameCount;
+      }
+      if (flags & 1) {
+        binaryFramePart = { sessionId, sequence, frameCount, buffers: [pcmBuffer] };
+        continue;
+      }
 
       const wc = captureSessionOwners.get(sessionId);
       if (wc && !wc.isDestroyed()) {
         wc.send('app-audio-frame-binary', {
           sessionId, targetId, sequence, sampleRate,
           channels, frameCount, protocolVersion, droppedFrameCount,
-          pcmBuffer
+          tag, pcmBuffer
         });
       }
     } catch (e) {